@@ -2,8 +2,8 @@ use super::*;
 
 use slab;
 
-use std::ops;
-use std::collections::{HashMap, hash_map};
+use std::ops::{self, RangeBounds};
+use std::collections::{BTreeMap, HashMap, hash_map};
 use std::marker::PhantomData;
 
 /// Storage for streams
@@ -11,6 +11,21 @@ use std::marker::PhantomData;
 pub(super) struct Store<B> {
     slab: slab::Slab<Stream<B>>,
     ids: HashMap<StreamId, usize>,
+    // Secondary index, ordered by `StreamId`, kept in lock-step with `ids` so
+    // that range queries (GOAWAY, priority sweeps, ...) don't have to walk
+    // the whole hash map.
+    ordered_ids: BTreeMap<StreamId, Key>,
+    // Maximum number of streams allowed to be live at once. Defaults to
+    // unbounded; set via `with_max`.
+    max: usize,
+    // Intrusive, insertion-ordered list threading every stream currently in
+    // the store, so that traversal order is deterministic regardless of hash
+    // or `StreamId` ordering. Maintained in `insert` / `remove`.
+    created: Option<Indices>,
+    // Per-slab-slot generation counters, bumped each time a slot is freed.
+    // Lets `Key` detect a slot that has since been recycled for a different
+    // stream (an ABA hazard for any cached `Key`).
+    generations: Vec<u32>,
 }
 
 /// "Pointer" to an entry in the store
@@ -20,8 +35,16 @@ pub(super) struct Ptr<'a, B: 'a> {
 }
 
 /// References an entry in the store.
+///
+/// Carries a generation counter alongside the raw slab index so that a
+/// `Key` cached elsewhere (a queue node, a pending reset) can be detected as
+/// stale once its slot has been freed and recycled for a different stream,
+/// rather than silently resolving to whatever now lives there.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(super) struct Key(usize);
+pub(super) struct Key {
+    index: usize,
+    generation: u32,
+}
 
 #[derive(Debug)]
 pub(super) struct List<B> {
@@ -37,6 +60,26 @@ pub(super) trait Next {
     fn take_next<B>(stream: &mut Stream<B>) -> Option<Key>;
 }
 
+pub(super) trait Prev {
+    fn prev<B>(stream: &Stream<B>) -> Option<Key>;
+
+    fn set_prev<B>(stream: &mut Stream<B>, key: Option<Key>);
+
+    fn take_prev<B>(stream: &mut Stream<B>) -> Option<Key>;
+}
+
+/// Marker for the intrusive, insertion-ordered "all streams" list threaded
+/// through every `Stream` in a `Store`. Never instantiated; only used to
+/// select the `Next`/`Prev` impl that stores these links on `Stream`.
+pub(super) enum NextAll {}
+
+/// Marker for the outbound scheduling ready-queue: the doubly-linked list a
+/// weight-based writer walks (and reorders via `move_to_front`/
+/// `move_to_back`) as PRIORITY frames change a stream's position. Never
+/// instantiated; only used to select the `Next`/`Prev` impl that stores
+/// these links on `Stream`.
+pub(super) enum NextOutbound {}
+
 /// A linked list
 #[derive(Debug, Clone, Copy)]
 struct Indices {
@@ -52,11 +95,26 @@ pub(super) enum Entry<'a, B: 'a> {
 pub(super) struct OccupiedEntry<'a, B: 'a> {
     ids: hash_map::OccupiedEntry<'a, StreamId, usize>,
     slab: &'a mut slab::Slab<Stream<B>>,
+    generations: &'a Vec<u32>,
 }
 
 pub(super) struct VacantEntry<'a, B: 'a> {
     ids: hash_map::VacantEntry<'a, StreamId, usize>,
     slab: &'a mut slab::Slab<Stream<B>>,
+    ordered_ids: &'a mut BTreeMap<StreamId, Key>,
+    created: &'a mut Option<Indices>,
+    generations: &'a mut Vec<u32>,
+}
+
+/// Iterator over a range of streams, in `StreamId` order.
+///
+/// Returned by `Store::range_mut`. This is *not* a `std::iter::Iterator`:
+/// each `Ptr` it yields borrows the whole store for as long as it's alive,
+/// so only one can be live at a time. Drive it with
+/// `while let Some(stream) = range.next() { ... }` rather than a `for` loop.
+pub(super) struct RangeMut<'a, B: 'a> {
+    store: &'a mut Store<B>,
+    keys: ::std::vec::IntoIter<Key>,
 }
 
 // ===== impl Store =====
@@ -66,20 +124,65 @@ impl<B> Store<B> {
         Store {
             slab: slab::Slab::new(),
             ids: HashMap::new(),
+            ordered_ids: BTreeMap::new(),
+            max: usize::max_value(),
+            created: None,
+            generations: Vec::new(),
+        }
+    }
+
+    /// Returns a `Store` whose `try_insert` refuses new streams once `max`
+    /// streams are live, for bounding MAX_CONCURRENT_STREAMS. `insert` and
+    /// `find_entry`'s vacant-entry insert do not check the limit.
+    pub fn with_max(max: usize) -> Self {
+        Store {
+            max: max,
+            .. Store::new()
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.max
+    }
+
+    /// Resolves a freshly-obtained `Key` to a `Ptr`. `key` must not have
+    /// outlived the stream it was created for; use `get_mut` when that
+    /// can't be guaranteed (e.g. a `Key` cached by another subsystem).
     pub fn resolve(&mut self, key: Key) -> Ptr<B> {
+        debug_assert_eq!(
+            self.generations[key.index], key.generation,
+            "Store::resolve called with a stale Key"
+        );
+
         Ptr {
             key: key,
             store: self,
         }
     }
 
+    /// Like `resolve`, but returns `None` instead of panicking if `key`'s
+    /// slot has since been freed and recycled for a different stream.
+    pub fn get_mut(&mut self, key: Key) -> Option<Ptr<B>> {
+        if self.generations.get(key.index) != Some(&key.generation) {
+            return None;
+        }
+
+        Some(Ptr {
+            key: key,
+            store: self,
+        })
+    }
+
     pub fn find_mut(&mut self, id: &StreamId) -> Option<Ptr<B>> {
-        if let Some(&key) = self.ids.get(id) {
+        if let Some(&index) = self.ids.get(id) {
+            let key = Key { index: index, generation: self.generations[index] };
+
             Some(Ptr {
-                key: Key(key),
+                key: key,
                 store: self,
             })
         } else {
@@ -88,15 +191,111 @@ impl<B> Store<B> {
     }
 
     pub fn insert(&mut self, id: StreamId, val: Stream<B>) -> Ptr<B> {
-        let key = self.slab.insert(val);
-        assert!(self.ids.insert(id, key).is_none());
+        let index = self.slab.insert(val);
+        assert!(self.ids.insert(id, index).is_none());
+
+        let key = Self::key_for(&mut self.generations, index);
+        self.ordered_ids.insert(id, key);
+        Self::link_created(&mut self.slab, &mut self.created, key);
 
         Ptr {
-            key: Key(key),
+            key: key,
             store: self,
         }
     }
 
+    /// Removes the stream referenced by `key`, freeing its slab slot and
+    /// bumping the slot's generation so that any other `Key` still pointing
+    /// at it is detected as stale rather than resolving to whatever stream
+    /// is allocated into the slot next.
+    pub fn remove(&mut self, key: Key) -> Stream<B> {
+        debug_assert_eq!(
+            self.generations[key.index], key.generation,
+            "Store::remove called with a stale Key"
+        );
+
+        Self::unlink_created(&mut self.slab, &mut self.created, key);
+
+        let id = self.slab[key.index].id;
+        self.ids.remove(&id);
+        self.ordered_ids.remove(&id);
+
+        self.generations[key.index] = self.generations[key.index].wrapping_add(1);
+
+        self.slab.remove(key.index)
+    }
+
+    // Returns the slot's `Key`, registering a fresh generation if this is
+    // the slot's first use.
+    fn key_for(generations: &mut Vec<u32>, index: usize) -> Key {
+        if index == generations.len() {
+            generations.push(0);
+        }
+
+        Key { index: index, generation: generations[index] }
+    }
+
+    // Appends `key` to the tail of the insertion-ordered "all streams" list.
+    fn link_created(
+        slab: &mut slab::Slab<Stream<B>>,
+        created: &mut Option<Indices>,
+        key: Key,
+    ) {
+        match *created {
+            Some(ref mut idxs) => {
+                NextAll::set_next(&mut slab[idxs.tail.index], Some(key));
+                NextAll::set_prev(&mut slab[key.index], Some(idxs.tail));
+                idxs.tail = key;
+            }
+            None => {
+                *created = Some(Indices { head: key, tail: key });
+            }
+        }
+    }
+
+    // Unlinks `key` from the insertion-ordered "all streams" list.
+    fn unlink_created(
+        slab: &mut slab::Slab<Stream<B>>,
+        created: &mut Option<Indices>,
+        key: Key,
+    ) {
+        if let Some(mut idxs) = *created {
+            let prev = NextAll::take_prev(&mut slab[key.index]);
+            let next = NextAll::take_next(&mut slab[key.index]);
+
+            match prev {
+                Some(prev) => NextAll::set_next(&mut slab[prev.index], next),
+                None => {
+                    match next {
+                        Some(next) => idxs.head = next,
+                        None => {
+                            *created = None;
+                            return;
+                        }
+                    }
+                }
+            }
+
+            match next {
+                Some(next) => NextAll::set_prev(&mut slab[next.index], prev),
+                None => idxs.tail = prev.unwrap(),
+            }
+
+            *created = Some(idxs);
+        }
+    }
+
+    /// Inserts `val`, unless the store is already at its configured `max`
+    /// capacity, in which case `val` is handed back to the caller so it can
+    /// be used to emit a REFUSED_STREAM rather than being dropped.
+    pub fn try_insert(&mut self, id: StreamId, val: Stream<B>) -> Result<Ptr<B>, Stream<B>> {
+        if self.is_full() {
+            return Err(val);
+        }
+
+        Ok(self.insert(id, val))
+    }
+
     pub fn find_entry(&mut self, id: StreamId) -> Entry<B> {
         use self::hash_map::Entry::*;
 
@@ -105,12 +304,16 @@ impl<B> Store<B> {
                 Entry::Occupied(OccupiedEntry {
                     ids: e,
                     slab: &mut self.slab,
+                    generations: &self.generations,
                 })
             }
             Vacant(e) => {
                 Entry::Vacant(VacantEntry {
                     ids: e,
                     slab: &mut self.slab,
+                    ordered_ids: &mut self.ordered_ids,
+                    created: &mut self.created,
+                    generations: &mut self.generations,
                 })
             }
         }
@@ -123,19 +326,97 @@ impl<B> Store<B> {
             f(&mut self.slab[id])
         }
     }
+
+    /// Iterate over all streams in the order they were inserted into the
+    /// store. Unlike `for_each`, this gives a deterministic, reproducible
+    /// servicing order independent of hash iteration order.
+    pub fn for_each_ordered<F>(&mut self, mut f: F)
+        where F: FnMut(&mut Stream<B>)
+    {
+        let mut next = self.created.map(|idxs| idxs.head);
+
+        while let Some(key) = next {
+            next = NextAll::next(&self.slab[key.index]);
+            f(&mut self.slab[key.index]);
+        }
+    }
+
+    /// Iterate over all streams in ascending `StreamId` order.
+    pub fn for_each_id_ordered<F>(&mut self, mut f: F)
+        where F: FnMut(&mut Stream<B>)
+    {
+        for &key in self.ordered_ids.values() {
+            f(&mut self.slab[key.index])
+        }
+    }
+
+    /// Returns the `Key`s, in ascending `StreamId` order, of the streams
+    /// whose ID falls within `range`.
+    ///
+    /// A real `std::iter::Iterator`, so it composes with adapters and
+    /// `for` loops; resolve each `Key` with `resolve`/`get_mut` as needed.
+    /// Prefer `range_mut` when every matched stream needs mutating.
+    pub fn range<R>(&self, range: R) -> ::std::vec::IntoIter<Key>
+        where R: RangeBounds<StreamId>,
+    {
+        self.ordered_ids.range(range)
+            .map(|(_, &key)| key)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Returns a lending iterator, in ascending `StreamId` order, over the
+    /// streams whose ID falls within `range`.
+    ///
+    /// This is the O(log n + k) alternative to `for_each` for operations
+    /// like GOAWAY handling that only care about streams above or below a
+    /// given ID. It cannot be a `std::iter::Iterator` (each yielded `Ptr`
+    /// borrows the whole store) despite the name suggesting one; use
+    /// `range` instead when only the `Key`s are needed.
+    pub fn range_mut<R>(&mut self, range: R) -> RangeMut<B>
+        where R: RangeBounds<StreamId>,
+    {
+        let keys: Vec<Key> = self.ordered_ids.range(range)
+            .map(|(_, &key)| key)
+            .collect();
+
+        RangeMut {
+            store: self,
+            keys: keys.into_iter(),
+        }
+    }
 }
 
 impl<B> ops::Index<Key> for Store<B> {
     type Output = Stream<B>;
 
     fn index(&self, key: Key) -> &Self::Output {
-        self.slab.index(key.0)
+        debug_assert_eq!(
+            self.generations[key.index], key.generation,
+            "Key resolved to a stale or reused stream slot"
+        );
+
+        self.slab.index(key.index)
     }
 }
 
 impl<B> ops::IndexMut<Key> for Store<B> {
     fn index_mut(&mut self, key: Key) -> &mut Self::Output {
-        self.slab.index_mut(key.0)
+        debug_assert_eq!(
+            self.generations[key.index], key.generation,
+            "Key resolved to a stale or reused stream slot"
+        );
+
+        self.slab.index_mut(key.index)
+    }
+}
+
+// ===== impl RangeMut =====
+
+impl<'a, B: 'a> RangeMut<'a, B> {
+    pub fn next(&mut self) -> Option<Ptr<B>> {
+        let key = self.keys.next()?;
+        Some(self.store.resolve(key))
     }
 }
 
@@ -161,10 +442,11 @@ impl<B> List<B> {
     }
 
     pub fn push<N>(&mut self, stream: &mut store::Ptr<B>)
-        where N: Next,
+        where N: Next + Prev,
     {
-        // The next pointer shouldn't be set
+        // Neither pointer should be set
         debug_assert!(N::next(stream).is_none());
+        debug_assert!(N::prev(stream).is_none());
 
         // Queue the stream
         match self.indices {
@@ -172,6 +454,7 @@ impl<B> List<B> {
                 // Update the current tail node to point to `stream`
                 let key = stream.key();
                 N::set_next(&mut stream.resolve(idxs.tail), Some(key));
+                N::set_prev(stream, Some(idxs.tail));
 
                 // Update the tail pointer
                 idxs.tail = stream.key();
@@ -186,65 +469,152 @@ impl<B> List<B> {
     }
 
     pub fn pop<'a, N>(&mut self, store: &'a mut Store<B>) -> Option<store::Ptr<'a, B>>
-        where N: Next,
+        where N: Next + Prev,
     {
         if let Some(mut idxs) = self.indices {
-            let mut stream = store.resolve(idxs.head);
+            let head = idxs.head;
 
             if idxs.head == idxs.tail {
-                assert!(N::next(&*stream).is_none());
+                assert!(N::next(&store[head]).is_none());
                 self.indices = None;
             } else {
-                idxs.head = N::take_next(&mut *stream).unwrap();
+                let next = N::take_next(&mut store[head]).unwrap();
+                N::set_prev(&mut store[next], None);
+                idxs.head = next;
                 self.indices = Some(idxs);
             }
 
-            return Some(stream);
+            return Some(store.resolve(head));
         }
 
         None
     }
 
+    /// Unlinks `key` from the list in O(1), patching its neighbors' next/prev
+    /// pointers. The node's own next/prev are cleared. `key` must currently
+    /// be a member of this list.
+    pub fn remove<N>(&mut self, store: &mut Store<B>, key: Key)
+        where N: Next + Prev,
+    {
+        if let Some(mut idxs) = self.indices {
+            let prev = N::take_prev(&mut store[key]);
+            let next = N::take_next(&mut store[key]);
+
+            match prev {
+                Some(prev) => N::set_next(&mut store[prev], next),
+                None => {
+                    match next {
+                        Some(next) => idxs.head = next,
+                        None => {
+                            // `key` was the only element
+                            self.indices = None;
+                            return;
+                        }
+                    }
+                }
+            }
+
+            match next {
+                Some(next) => N::set_prev(&mut store[next], prev),
+                None => idxs.tail = prev.unwrap(),
+            }
+
+            self.indices = Some(idxs);
+        }
+    }
+
+    /// Moves `key` to the front of the list in O(1).
+    pub fn move_to_front<N>(&mut self, store: &mut Store<B>, key: Key)
+        where N: Next + Prev,
+    {
+        self.remove::<N>(store, key);
+        self.push_front::<N>(store, key);
+    }
+
+    /// Moves `key` to the back of the list in O(1).
+    pub fn move_to_back<N>(&mut self, store: &mut Store<B>, key: Key)
+        where N: Next + Prev,
+    {
+        self.remove::<N>(store, key);
+        self.push_back::<N>(store, key);
+    }
+
+    fn push_front<N>(&mut self, store: &mut Store<B>, key: Key)
+        where N: Next + Prev,
+    {
+        debug_assert!(N::next(&store[key]).is_none());
+        debug_assert!(N::prev(&store[key]).is_none());
+
+        match self.indices {
+            Some(ref mut idxs) => {
+                N::set_prev(&mut store[idxs.head], Some(key));
+                N::set_next(&mut store[key], Some(idxs.head));
+                idxs.head = key;
+            }
+            None => {
+                self.indices = Some(store::Indices { head: key, tail: key });
+            }
+        }
+    }
+
+    fn push_back<N>(&mut self, store: &mut Store<B>, key: Key)
+        where N: Next + Prev,
+    {
+        debug_assert!(N::next(&store[key]).is_none());
+        debug_assert!(N::prev(&store[key]).is_none());
+
+        match self.indices {
+            Some(ref mut idxs) => {
+                N::set_next(&mut store[idxs.tail], Some(key));
+                N::set_prev(&mut store[key], Some(idxs.tail));
+                idxs.tail = key;
+            }
+            None => {
+                self.indices = Some(store::Indices { head: key, tail: key });
+            }
+        }
+    }
+
     pub fn retain<N, F>(&mut self, store: &mut Store<B>, mut f: F)
-        where N: Next,
+        where N: Next + Prev,
               F: FnMut(&mut Stream<B>) -> bool,
     {
         if let Some(mut idxs) = self.indices {
             let mut prev = None;
-            let mut curr = idxs.head;
+            let mut curr = Some(idxs.head);
 
-            loop {
-                if f(&mut store[curr]) {
+            while let Some(key) = curr {
+                if f(&mut store[key]) {
                     // Element is retained, walk to the next
-                    if let Some(next) = N::next(&mut store[curr]) {
-                        prev = Some(curr);
-                        curr = next;
-                    } else {
-                        // Tail
-                        break;
-                    }
+                    prev = Some(key);
+                    curr = N::next(&store[key]);
                 } else {
-                    // Element is dropped
-                    if let Some(prev) = prev {
-                        let next = N::take_next(&mut store[curr]);
-                        N::set_next(&mut store[prev], next);
-
-                        // current is last element, but guaranteed to not be the
-                        // only one
-                        if next.is_none() {
-                            idxs.tail = prev;
-                            break;
-                        }
-                    } else {
-                        if let Some(next) = N::take_next(&mut store[curr]) {
-                            curr = next;
-                            idxs.head = next;
-                        } else {
-                            // Only element
-                            self.indices = None;
-                            return;
+                    // Element is dropped; unlink it, patching both
+                    // neighbors so surviving nodes don't keep a stale
+                    // prev/next into the slot `key` will free.
+                    let next = N::take_next(&mut store[key]);
+                    N::take_prev(&mut store[key]);
+
+                    match prev {
+                        Some(prev) => N::set_next(&mut store[prev], next),
+                        None => {
+                            match next {
+                                Some(next) => idxs.head = next,
+                                None => {
+                                    // Only element
+                                    self.indices = None;
+                                    return;
+                                }
+                            }
                         }
                     }
+
+                    match next {
+                        Some(next) => N::set_prev(&mut store[next], prev),
+                        None => idxs.tail = prev.unwrap(),
+                    }
+
+                    curr = next;
                 }
             }
 
@@ -272,7 +642,12 @@ impl<'a, B: 'a> Ptr<'a, B> {
     }
 
     pub fn into_mut(self) -> &'a mut Stream<B> {
-        &mut self.store.slab[self.key.0]
+        debug_assert_eq!(
+            self.store.generations[self.key.index], self.key.generation,
+            "Key resolved to a stale or reused stream slot"
+        );
+
+        &mut self.store.slab[self.key.index]
     }
 }
 
@@ -280,13 +655,23 @@ impl<'a, B: 'a> ops::Deref for Ptr<'a, B> {
     type Target = Stream<B>;
 
     fn deref(&self) -> &Stream<B> {
-        &self.store.slab[self.key.0]
+        debug_assert_eq!(
+            self.store.generations[self.key.index], self.key.generation,
+            "Key resolved to a stale or reused stream slot"
+        );
+
+        &self.store.slab[self.key.index]
     }
 }
 
 impl<'a, B: 'a> ops::DerefMut for Ptr<'a, B> {
     fn deref_mut(&mut self) -> &mut Stream<B> {
-        &mut self.store.slab[self.key.0]
+        debug_assert_eq!(
+            self.store.generations[self.key.index], self.key.generation,
+            "Key resolved to a stale or reused stream slot"
+        );
+
+        &mut self.store.slab[self.key.index]
     }
 }
 
@@ -294,7 +679,8 @@ impl<'a, B: 'a> ops::DerefMut for Ptr<'a, B> {
 
 impl<'a, B> OccupiedEntry<'a, B> {
     pub fn key(&self) -> Key {
-        Key(*self.ids.get())
+        let index = *self.ids.get();
+        Key { index: index, generation: self.generations[index] }
     }
 
     pub fn get(&self) -> &Stream<B> {
@@ -314,12 +700,20 @@ impl<'a, B> OccupiedEntry<'a, B> {
 
 impl<'a, B> VacantEntry<'a, B> {
     pub fn insert(self, value: Stream<B>) -> Key {
+        // The ID map's vacant entry doesn't let us keep a reference to the
+        // key once `self.ids` is consumed below, so grab a copy first.
+        let id = *self.ids.key();
+
         // Insert the value in the slab
         let key = self.slab.insert(value);
 
         // Insert the handle in the ID map
         self.ids.insert(key);
 
-        Key(key)
+        let key = Store::key_for(self.generations, key);
+        self.ordered_ids.insert(id, key);
+        Store::link_created(self.slab, self.created, key);
+
+        key
     }
 }
\ No newline at end of file
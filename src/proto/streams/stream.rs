@@ -0,0 +1,80 @@
+use super::*;
+use super::store::{Key, Next, Prev, NextAll, NextOutbound};
+
+use std::marker::PhantomData;
+
+/// State associated with an HTTP/2 stream.
+///
+/// Only the fields that `store.rs`'s intrusive lists and indices reach into
+/// are represented here.
+#[derive(Debug)]
+pub(super) struct Stream<B> {
+    /// The h2 stream identifier.
+    pub id: StreamId,
+
+    /// Links for the outbound scheduling ready-queue (`NextOutbound`).
+    next_outbound: Option<Key>,
+    prev_outbound: Option<Key>,
+
+    /// Links for the insertion-ordered "all streams" list (`NextAll`).
+    next_all: Option<Key>,
+    prev_all: Option<Key>,
+
+    _p: PhantomData<B>,
+}
+
+impl Next for NextOutbound {
+    fn next<B>(stream: &Stream<B>) -> Option<Key> {
+        stream.next_outbound
+    }
+
+    fn set_next<B>(stream: &mut Stream<B>, key: Option<Key>) {
+        stream.next_outbound = key;
+    }
+
+    fn take_next<B>(stream: &mut Stream<B>) -> Option<Key> {
+        stream.next_outbound.take()
+    }
+}
+
+impl Prev for NextOutbound {
+    fn prev<B>(stream: &Stream<B>) -> Option<Key> {
+        stream.prev_outbound
+    }
+
+    fn set_prev<B>(stream: &mut Stream<B>, key: Option<Key>) {
+        stream.prev_outbound = key;
+    }
+
+    fn take_prev<B>(stream: &mut Stream<B>) -> Option<Key> {
+        stream.prev_outbound.take()
+    }
+}
+
+impl Next for NextAll {
+    fn next<B>(stream: &Stream<B>) -> Option<Key> {
+        stream.next_all
+    }
+
+    fn set_next<B>(stream: &mut Stream<B>, key: Option<Key>) {
+        stream.next_all = key;
+    }
+
+    fn take_next<B>(stream: &mut Stream<B>) -> Option<Key> {
+        stream.next_all.take()
+    }
+}
+
+impl Prev for NextAll {
+    fn prev<B>(stream: &Stream<B>) -> Option<Key> {
+        stream.prev_all
+    }
+
+    fn set_prev<B>(stream: &mut Stream<B>, key: Option<Key>) {
+        stream.prev_all = key;
+    }
+
+    fn take_prev<B>(stream: &mut Stream<B>) -> Option<Key> {
+        stream.prev_all.take()
+    }
+}